@@ -0,0 +1,74 @@
+/* Cross-hart remote function calls, modeled on U-Boot's `smp_call_function`.
+ *
+ * The caller stores a function pointer (plus argument) into the target
+ * hart's slot and fires a supervisor software interrupt at it via
+ * `sbi_rt::send_ipi`. The target hart's trap handler receives the interrupt,
+ * clears it, runs the queued call, and marks itself done so the caller can
+ * poll for completion. This lets the boot hart hand work to harts that would
+ * otherwise just sit in `wfi` after boot.
+ */
+
+use crate::start;
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::{sie, sip};
+use sbi_rt::HartMask;
+use spin::Mutex;
+
+struct Call {
+    func: fn(usize),
+    arg: usize,
+}
+
+static SLOTS: [Mutex<Option<Call>>; start::NCPU] = [const { Mutex::new(None) }; start::NCPU];
+static DONE: [AtomicBool; start::NCPU] = [const { AtomicBool::new(true) }; start::NCPU];
+
+/* Unmasks supervisor software interrupts for the calling hart. Must run
+ * after `trap::init` has installed `trap_entry`.
+ */
+pub fn init() {
+    unsafe {
+        sie::set_ssoft();
+    }
+}
+
+/* Queues `func(arg)` to run on `hartid` and wakes it with an IPI. Panics if
+ * the SBI implementation refuses to deliver the IPI.
+ *
+ * `SLOTS`/`DONE` are indexed by dense stack index (0..NCPU, see
+ * `start::_start`), not the raw hartid, since hartid need not be small or
+ * contiguous; `crate::dense_index` resolves that for us. The SBI call itself
+ * still needs the real hartid, so it's passed as `HartMask`'s base with a
+ * single-hart mask of 1 rather than shifting by hartid, which could overflow
+ * for a large hartid.
+ */
+pub fn smp_call_function(hartid: usize, func: fn(usize), arg: usize) {
+    let dense_idx = crate::dense_index(hartid);
+
+    DONE[dense_idx].store(false, Ordering::SeqCst);
+    *SLOTS[dense_idx].lock() = Some(Call { func, arg });
+
+    sbi_rt::send_ipi(HartMask::from_mask_base(1, hartid))
+        .into_result()
+        .unwrap_or_else(|_| panic!("Failed to send IPI to hart {}", hartid));
+}
+
+/// Lets the caller poll for the queued call on `hartid` having completed.
+pub fn call_done(hartid: usize) -> bool {
+    DONE[crate::dense_index(hartid)].load(Ordering::SeqCst)
+}
+
+/* Called from the trap handler on every supervisor software interrupt for
+ * `dense_idx`: acknowledges the IPI, runs the queued call if any, and marks
+ * this hart done.
+ */
+pub fn on_ipi(dense_idx: usize) {
+    unsafe {
+        sip::clear_ssoft();
+    }
+
+    if let Some(call) = SLOTS[dense_idx].lock().take() {
+        (call.func)(call.arg);
+    }
+
+    DONE[dense_idx].store(true, Ordering::SeqCst);
+}
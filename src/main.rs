@@ -1,14 +1,18 @@
 #![no_std]
 #![no_main]
 
+mod ipi;
 mod start;
+mod timer;
+mod trap;
 use start::_start;
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use fdt::Fdt;
-use heapless::String;
+use heapless::{String, Vec};
+use riscv::register::time;
 use sbi_rt::Physical;
 use spin::Once;
 
@@ -21,6 +25,56 @@ use spin::Once;
 static DEVTREE: Once<Fdt> = Once::new();
 static STARTED: AtomicBool = AtomicBool::new(false);
 static NCPU: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static TIMEBASE_FREQ: AtomicUsize = AtomicUsize::new(0);
+
+/* The real hartids discovered from /cpus, in device-tree order. Hartids need
+ * not be contiguous or equal in count to NCPU, so `start_harts` looks up
+ * each hart here rather than assuming a 0..NCPU range.
+ */
+static HARTIDS: Once<Vec<usize, { start::NCPU }>> = Once::new();
+
+/* Boot handshake, closing the `hart_start` START_PENDING race: a woken
+ * secondary hart sets its bit in ONLINE and then spins on GO before it
+ * touches anything derived from the device tree. The boot hart only sets GO
+ * once every expected hart has published its ONLINE bit, so by the time any
+ * hart proceeds, the boot hart is guaranteed to have finished `handle_dtb`.
+ * Bit position is the hart's dense stack index (0..NCPU, see `start::_start`),
+ * not its raw hartid, since hartids need not be contiguous or small.
+ */
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+static GO: AtomicBool = AtomicBool::new(false);
+
+/* The real hartid of whichever hart won the boot lottery, recorded so
+ * `dense_index` can translate any other hartid into the dense stack index
+ * `start_harts` assigned it.
+ */
+static BOOT_HARTID: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/* Maps a real hartid to the dense index (0..NCPU) `start_harts` assigned it:
+ * 0 for the boot hart, then 1.. for the rest in device-tree order. This is
+ * the single source of truth for that mapping; `start_harts` and `ipi`'s
+ * `smp_call_function` both call it rather than recomputing it themselves.
+ */
+pub(crate) fn dense_index(hartid: usize) -> usize {
+    let boothartid = BOOT_HARTID.load(Ordering::SeqCst);
+    if hartid == boothartid {
+        return 0;
+    }
+
+    let hartids = HARTIDS.get().expect("HARTIDS not populated by handle_dtb");
+    let mut idx = 1;
+    for &h in hartids.iter() {
+        if h == boothartid {
+            continue;
+        }
+        if h == hartid {
+            return idx;
+        }
+        idx += 1;
+    }
+
+    panic!("hartid {} not in discovered hart list", hartid);
+}
 
 /* Formats a string and passes the physical address of that string to an SBI call
  * for printing to a debug console. In QEMUs case, this would be the memory-mapped UART.
@@ -69,32 +123,49 @@ fn handle_dtb(dtb: *const u8) {
         .next()
         .map(|dram| dram.starting_address)
         .expect("Unable to locate DRAM start");
+    let timebase_freq = dt
+        .cpus()
+        .next()
+        .map(|cpu| cpu.timebase_frequency())
+        .expect("Unable to locate /cpus timebase-frequency");
 
     debug_print!("Device tree info:\n");
     debug_print!("Model: {}\n", model);
     debug_print!("No. CPUs: {}\n", ncpus);
     debug_print!("DRAM start: {:p}\n", mem);
+    debug_print!("Timebase frequency: {} Hz\n", timebase_freq);
+
+    // Parse the real hartid of each CPU out of its `reg` property
+    let hartids = HARTIDS.call_once(|| {
+        let mut ids = Vec::new();
+        for cpu in dt.cpus() {
+            ids.push(cpu.ids().first())
+                .unwrap_or_else(|_| panic!("More harts in device tree than NCPU ({})", start::NCPU));
+        }
+        ids
+    });
 
-    // Store number of CPUs for later
+    // Store number of CPUs and the timebase frequency for later
     NCPU.store(ncpus, Ordering::SeqCst);
+    TIMEBASE_FREQ.store(timebase_freq, Ordering::SeqCst);
 }
 
 fn start_harts(boothartid: usize) {
-    for h in 0..NCPU.load(Ordering::SeqCst) {
+    let hartids = HARTIDS.get().expect("HARTIDS not populated by handle_dtb");
+
+    for &h in hartids.iter() {
+        if h == boothartid {
+            continue;
+        }
+
         /* Start the given hartid (will fail if already started).
-         *
-         * May actually need a better way to get all hartids since I believe
-         * hartids don't necessarily need to correspond to the number of harts
-         * (except there should always be a hart with hartid 0).
          *
          * We also pass the address of the entry point we wish harts to resume at,
          * and thus we want them to start at _start as well to initialize their stacks.
          *
-         * The "opaque" argument gets passed to a1. This is expected to be the pointer
-         * to the dtb from main code, but we don't want to access it if not boot hart
-         * so just pass 0 as a fail-safe to force panic if hart tries to deref it.
-         *
-         * Not sure if this is the "proper" way to wake harts but could not find much else.
+         * The "opaque" argument gets passed to a1 and is used by _start to pick this
+         * hart's dense stack index (via `dense_index`) rather than the pointer to the
+         * dtb, since secondary harts have no business dereferencing the dtb anyway.
          *
          * Wrapper around:
          *
@@ -102,24 +173,42 @@ fn start_harts(boothartid: usize) {
          * li a6, {FID=0x0}
          * li a0, {hartid=h}
          * li a1, {start_addr=_start}
-         * li a2, {opaque=0}
+         * li a2, {opaque=dense_index(h)}
          * ecall
          */
-        if h != boothartid {
-            sbi_rt::hart_start(h, _start as usize, 0)
-                .into_result()
-                .unwrap_or_else(|_| panic!("Failed to start hart {}", h));
-        }
+        sbi_rt::hart_start(h, _start as usize, dense_index(h))
+            .into_result()
+            .unwrap_or_else(|_| panic!("Failed to start hart {}", h));
     }
 }
 
-/* main is called by _start, and RISCV calling conventions state that the first two arguments
- * should correspond to registers a0 and a1 if they fit. Conveniently, OpenSBI places the hartid
- * and pointer to DTB in these registers which _start doesn't clobber and are thus accessible
- * from main.
+/* Trivial payload for the `smp_call_function` demo dispatch below: just
+ * proves a remote hart actually ran it.
+ */
+fn ipi_demo(arg: usize) {
+    debug_print!("Hart handled smp_call_function(arg={})\n", arg);
+}
+
+/* Asks the SBI System Reset extension to shut down (or reboot) the machine
+ * so `qemu-system-riscv64` actually exits instead of wedging a hart forever.
+ * Firmware that doesn't implement SRST will just return an error from the
+ * ecall, so fall back to spinning in that case.
+ */
+fn shutdown(reset_type: sbi_rt::ResetType, reason: sbi_rt::ResetReason) -> ! {
+    sbi_rt::system_reset(reset_type, reason);
+
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+/* main is called by _start, and RISCV calling conventions state that the first three arguments
+ * should correspond to registers a0, a1 and a2 if they fit. Conveniently, OpenSBI places the
+ * hartid and pointer to DTB in a0/a1, and `_start` places our resolved dense stack index (see
+ * its comments) in a2, none of which it clobbers, so all three are accessible from main.
  */
 #[no_mangle]
-extern "C" fn main(hartid: usize, dtb: *const u8) -> ! {
+extern "C" fn main(hartid: usize, dtb: *const u8, dense_idx: usize) -> ! {
     match STARTED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
         /* If we are the boot hart, parse device tree and start other harts.
          *
@@ -131,22 +220,86 @@ extern "C" fn main(hartid: usize, dtb: *const u8) -> ! {
             debug_print!("Hack the planet!\n");
             debug_print!("Boot hart: {}\n\n", hartid);
 
+            BOOT_HARTID.store(hartid, Ordering::SeqCst);
+
             handle_dtb(dtb);
             debug_print!("\n");
 
             start_harts(hartid);
+
+            // We count as online too; wait for every woken hart to publish
+            // its bit before releasing everyone to touch the device tree's
+            // data, which is only safe to read once `handle_dtb` has run.
+            ONLINE.fetch_or(1 << dense_idx, Ordering::SeqCst);
+
+            let nharts = HARTIDS
+                .get()
+                .expect("HARTIDS not populated by handle_dtb")
+                .len();
+            let expected = (1usize << nharts) - 1;
+            while ONLINE.load(Ordering::SeqCst) & expected != expected {
+                core::hint::spin_loop();
+            }
+
+            GO.store(true, Ordering::Release);
         }
 
         // Otherwise do per-hart setup if needed (if not done in _start)
         Err(_) => {
             debug_print!("Hart {} starting...\n", hartid);
+
+            // Publish that we made it here, then wait for the boot hart to
+            // confirm every hart has before touching any DT-derived state.
+            ONLINE.fetch_or(1 << dense_idx, Ordering::SeqCst);
+            while !GO.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
         }
     }
 
-    // Finally do some real work (which all harts are now running in parallel)
-    loop {
-        riscv::asm::wfi();
+    // Every hart installs its own trap handler, arms its periodic tick, and
+    // unmasks IPIs so it can be handed work by smp_call_function
+    trap::init(dense_idx);
+    timer::init();
+    ipi::init();
+
+    /* The boot hart is the only one guaranteed a peer to call: demo-dispatch
+     * a trivial smp_call_function to the first secondary hart (if any) so
+     * the IPI send/poll path is actually exercised by something, rather than
+     * shipping as unused API surface. The wait for completion is bounded the
+     * same way as the tick wait below, since the target may not have reached
+     * `ipi::init` (and thus unmasked the interrupt) yet.
+     */
+    if dense_idx == 0 {
+        if let Some(&target) = HARTIDS
+            .get()
+            .expect("HARTIDS not populated by handle_dtb")
+            .iter()
+            .find(|&&h| h != hartid)
+        {
+            ipi::smp_call_function(target, ipi_demo, target);
+
+            let deadline = time::read() as u64 + timer::interval() * 2;
+            while !ipi::call_done(target) && (time::read() as u64) < deadline {
+                core::hint::spin_loop();
+            }
+        }
     }
+
+    // Finally do some real work (which all harts are now running in parallel)...
+    // but there isn't any yet. Still, shutting down the instant we get here
+    // would race every tick/IPI subsystem we just armed off the board before
+    // any of it could ever fire, so wait for at least one tick first to give
+    // them a chance to be observed. Bounded against the `time` CSR directly
+    // (not a count of `wfi` wakeups, which are not guaranteed to ever happen
+    // if no interrupt arrives) so this always terminates even if SBI TIME
+    // isn't implemented and no timer interrupt ever fires.
+    let deadline = time::read() as u64 + timer::interval() * 2;
+    while timer::ticks(dense_idx) == 0 && (time::read() as u64) < deadline {
+        core::hint::spin_loop();
+    }
+
+    shutdown(sbi_rt::Shutdown, sbi_rt::NoReason)
 }
 
 /* A simple panic handler that will get called any time a panic occurs
@@ -159,5 +312,5 @@ fn panic(info: &PanicInfo) -> ! {
     let line = info.location().map(|loc| loc.line()).unwrap_or(0);
 
     debug_print!("{} in {} at line {}\n", message, file, line);
-    loop {}
+    shutdown(sbi_rt::Shutdown, sbi_rt::SystemFailure)
 }
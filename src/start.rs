@@ -2,9 +2,17 @@
  * allocating stack-space at runtime. Could have also reserved stack-space in the linker
  * script but wanted to try to keep it in Rust.
  */
-const NCPU: usize = 3;
+pub(crate) const NCPU: usize = 3;
 const STKSZ: usize = 1024 * 64;
 
+/* Guards against NCPU ever being shrunk to something nonsensical; there is no
+ * compile-time way to bound NCPU against the number of harts the device tree
+ * will actually report, since that is only known at runtime. That bound is
+ * instead enforced where `handle_dtb` populates `HARTIDS`, a `heapless::Vec` whose
+ * capacity is tied to NCPU: pushing past it panics.
+ */
+const _: () = assert!(NCPU > 0, "NCPU must reserve at least one hart stack");
+
 /* Set aside statically allocated stack space for each hart.
  * Should be 16 byte aligned as per RISCV calling conventions.
  */
@@ -12,6 +20,21 @@ const STKSZ: usize = 1024 * 64;
 struct StaticStack([u8; STKSZ * NCPU]);
 static mut STACK0: StaticStack = StaticStack([0; STKSZ * NCPU]);
 
+/* Symbols exported by linker.ld marking the bounds of .bss and .data, plus
+ * the load address .data was placed at. In this image .data has no separate
+ * load region, so that load address and .data's run address are identical
+ * and the copy below is a no-op; we still do it so nothing breaks if
+ * linker.ld ever gives .data a real load region of its own. Only the boot
+ * hart touches these, and only before any secondary hart is started.
+ */
+extern "C" {
+    static mut __bss_start: u8;
+    static mut __bss_end: u8;
+    static mut __sdata: u8;
+    static mut __edata: u8;
+    static __sidata: u8;
+}
+
 /* This is the location OpenSBI will jump to. OpenSBI can be configured to load the next stage
  * several ways, but the default method in QEMU appears to be the DYNAMIC method. This seems to work
  * by analyzing our ELF passed via -kernel for the address of the beginning of .text. Thus
@@ -31,26 +54,82 @@ static mut STACK0: StaticStack = StaticStack([0; STKSZ * NCPU]);
 #[link_section = ".text"]
 pub extern "C" fn _start() {
     /* Don't want to clobber a0 and a1 as they hold hartid and DTB ptr passed by OpenSBI,
-     * so use a2 and a3 instead to store temporary values.
+     * so use t0-t4 for scratch and a3 for the resolved dense stack index, which we also
+     * hand to `main` as its 3rd argument (a2) so Rust code never has to re-derive it.
      */
     unsafe {
         core::arch::asm!(
+        /* Hartids need not be contiguous or start at 0, so `main` discovers
+         * the real hartids from the device tree and hands each secondary
+         * hart a dense stack index (0..NCPU) via `hart_start`'s opaque
+         * argument (a1) instead of using its raw hartid. We can tell the two
+         * cases apart because that index is always < NCPU, whereas the boot
+         * hart's a1 is OpenSBI's DTB pointer, which is never that small.
+         *
+         * The boot hart always reserves dense index 0 for itself: it is the
+         * only hart running at this point (secondaries are only told to
+         * start once the boot hart has parsed the device tree), so there is
+         * no index to look up yet and none to collide with.
+         */
+        "li t4, {ncpu}",
+        "bltu a1, t4, 6f",
+        "li a3, 0",
+        "j 7f",
+        "6:",
+        "mv a3, a1",
+        "7:",
+
         // Set stackpointer to base of STACK0 defined in Rust
         "la sp, {stack0}",
 
         // Store the stack size in a2
         "li a2, {stksz}",
 
-        // Add one to the current hartid
-        "addi a3, a0, 1",
+        // Add one to the dense stack index
+        "addi a4, a3, 1",
 
-        // Multiply the hartid by the stack size
-        "mul a2, a2, a3",
+        // Multiply the dense stack index by the stack size
+        "mul a2, a2, a4",
 
-        // Increment the stackpointer by hartid*stksz
+        // Increment the stackpointer by (index+1)*stksz
         // The stack wil now grow downwards from this point as per convention
         "add sp, sp, a2",
 
+        /* Only the boot hart (dense index 0 in a3) zeroes .bss and copies
+         * .data. This must happen before any secondary hart is started, so
+         * gating on a3 alone is enough: _start only ever runs for a
+         * secondary hart after hart_start, by which point the boot hart has
+         * already finished this section.
+         */
+        "bnez a3, 2f",
+
+        // Zero [__bss_start, __bss_end) a byte at a time
+        "la t0, {bss_start}",
+        "la t1, {bss_end}",
+        "3:",
+        "bgeu t0, t1, 4f",
+        "sb zero, 0(t0)",
+        "addi t0, t0, 1",
+        "j 3b",
+        "4:",
+
+        // Copy __sidata..(__edata-__sdata) into [__sdata, __edata)
+        "la t0, {sidata}",
+        "la t1, {sdata}",
+        "la t2, {edata}",
+        "5:",
+        "bgeu t1, t2, 2f",
+        "lb t3, 0(t0)",
+        "sb t3, 0(t1)",
+        "addi t0, t0, 1",
+        "addi t1, t1, 1",
+        "j 5b",
+
+        "2:",
+
+        // main's 3rd argument (a2) is our dense stack index, computed above
+        "mv a2, a3",
+
         // Call main defined in Rust
         "call main",
 
@@ -59,6 +138,12 @@ pub extern "C" fn _start() {
 
         stack0 = sym STACK0,
         stksz = const STKSZ,
+        ncpu = const NCPU,
+        bss_start = sym __bss_start,
+        bss_end = sym __bss_end,
+        sdata = sym __sdata,
+        edata = sym __edata,
+        sidata = sym __sidata,
         );
     }
 }
@@ -0,0 +1,53 @@
+/* Periodic supervisor timer interrupts, delivered via the SBI TIME extension.
+ *
+ * Each hart arms its own deadline with `sbi_rt::set_timer`; when the
+ * resulting supervisor timer interrupt fires, `trap::trap_handler` forwards
+ * it to `on_tick`, which bumps that hart's counter and re-arms the next
+ * deadline. The interval is derived from the timebase frequency read out of
+ * the device tree so it is wall-clock meaningful rather than a raw cycle
+ * count.
+ */
+
+use crate::start;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::{sie, sstatus, time};
+
+/// Desired tick rate; the actual SBI timer interval (in timebase ticks) is
+/// derived from this and the device tree's timebase-frequency.
+pub const TICK_HZ: u64 = 100;
+
+static TICKS: [AtomicUsize; start::NCPU] = [const { AtomicUsize::new(0) }; start::NCPU];
+
+/// Timebase ticks between deadlines; also useful to callers (e.g. `main`)
+/// that need to bound a wait against the `time` CSR in wall-clock terms.
+pub(crate) fn interval() -> u64 {
+    let freq = crate::TIMEBASE_FREQ.load(Ordering::SeqCst) as u64;
+    freq / TICK_HZ
+}
+
+/* Unmasks supervisor timer interrupts and arms the first deadline for the
+ * calling hart. Must run after `trap::init` has installed `trap_entry`.
+ */
+pub fn init() {
+    unsafe {
+        sie::set_stimer();
+        sstatus::set_sie();
+    }
+
+    let now = time::read() as u64;
+    sbi_rt::set_timer(now + interval());
+}
+
+/* Called from the trap handler on every supervisor timer interrupt for
+ * `dense_idx`: bumps its tick count and re-arms the next deadline.
+ */
+pub fn on_tick(dense_idx: usize) {
+    TICKS[dense_idx].fetch_add(1, Ordering::SeqCst);
+
+    let now = time::read() as u64;
+    sbi_rt::set_timer(now + interval());
+}
+
+pub fn ticks(dense_idx: usize) -> usize {
+    TICKS[dense_idx].load(Ordering::SeqCst)
+}
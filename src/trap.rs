@@ -0,0 +1,151 @@
+/* Supervisor-mode trap handling.
+ *
+ * `trap_entry` is installed into `stvec` (direct mode) during per-hart setup
+ * in `main`. It saves every GPR onto the current stack, hands off to the
+ * Rust-level `trap_handler`, restores the GPRs, and `sret`s back to whatever
+ * was interrupted. Timer and IPI support build on top of this by reading
+ * `scause` in `trap_handler` and dispatching to the right subsystem.
+ */
+
+use crate::debug_print;
+use riscv::register::{scause, sepc, sscratch, stval, stvec};
+
+// Interrupt cause codes from the privileged spec (low bits of `scause` once
+// the high "is interrupt" bit is stripped off).
+const INTERRUPT_SUPERVISOR_SOFT: usize = 1;
+const INTERRUPT_SUPERVISOR_TIMER: usize = 5;
+
+// x1, x3..x31 (x0 is hardwired zero, x2/sp is restored via the frame itself).
+const FRAME_SIZE: usize = 32 * core::mem::size_of::<usize>();
+
+core::arch::global_asm!(
+    ".align 2",
+    ".global trap_entry",
+    "trap_entry:",
+    "addi sp, sp, -{frame_size}",
+
+    "sd ra,  0*8(sp)",
+    "sd gp,  1*8(sp)",
+    "sd tp,  2*8(sp)",
+    "sd t0,  3*8(sp)",
+    "sd t1,  4*8(sp)",
+    "sd t2,  5*8(sp)",
+    "sd s0,  6*8(sp)",
+    "sd s1,  7*8(sp)",
+    "sd a0,  8*8(sp)",
+    "sd a1,  9*8(sp)",
+    "sd a2, 10*8(sp)",
+    "sd a3, 11*8(sp)",
+    "sd a4, 12*8(sp)",
+    "sd a5, 13*8(sp)",
+    "sd a6, 14*8(sp)",
+    "sd a7, 15*8(sp)",
+    "sd s2, 16*8(sp)",
+    "sd s3, 17*8(sp)",
+    "sd s4, 18*8(sp)",
+    "sd s5, 19*8(sp)",
+    "sd s6, 20*8(sp)",
+    "sd s7, 21*8(sp)",
+    "sd s8, 22*8(sp)",
+    "sd s9, 23*8(sp)",
+    "sd s10,24*8(sp)",
+    "sd s11,25*8(sp)",
+    "sd t3, 26*8(sp)",
+    "sd t4, 27*8(sp)",
+    "sd t5, 28*8(sp)",
+    "sd t6, 29*8(sp)",
+
+    "call {trap_handler}",
+
+    "ld ra,  0*8(sp)",
+    "ld gp,  1*8(sp)",
+    "ld tp,  2*8(sp)",
+    "ld t0,  3*8(sp)",
+    "ld t1,  4*8(sp)",
+    "ld t2,  5*8(sp)",
+    "ld s0,  6*8(sp)",
+    "ld s1,  7*8(sp)",
+    "ld a0,  8*8(sp)",
+    "ld a1,  9*8(sp)",
+    "ld a2, 10*8(sp)",
+    "ld a3, 11*8(sp)",
+    "ld a4, 12*8(sp)",
+    "ld a5, 13*8(sp)",
+    "ld a6, 14*8(sp)",
+    "ld a7, 15*8(sp)",
+    "ld s2, 16*8(sp)",
+    "ld s3, 17*8(sp)",
+    "ld s4, 18*8(sp)",
+    "ld s5, 19*8(sp)",
+    "ld s6, 20*8(sp)",
+    "ld s7, 21*8(sp)",
+    "ld s8, 22*8(sp)",
+    "ld s9, 23*8(sp)",
+    "ld s10,24*8(sp)",
+    "ld s11,25*8(sp)",
+    "ld t3, 26*8(sp)",
+    "ld t4, 27*8(sp)",
+    "ld t5, 28*8(sp)",
+    "ld t6, 29*8(sp)",
+
+    "addi sp, sp, {frame_size}",
+    "sret",
+
+    frame_size = const FRAME_SIZE,
+    trap_handler = sym trap_handler,
+);
+
+extern "C" {
+    fn trap_entry();
+}
+
+/* Installs `trap_entry` into `stvec` in direct mode (low 2 bits clear) for
+ * the calling hart, and stashes its dense stack index (0..NCPU, see
+ * `start::_start`) in `sscratch` so `trap_handler` can recover which hart
+ * it is running on (S-mode cannot read `mhartid`) and pass it on to the
+ * `timer`/`ipi` subsystems, whose per-hart arrays are sized and indexed by
+ * that same dense index rather than the raw hartid. Must be called once
+ * per hart during its setup in `main`.
+ */
+pub fn init(dense_idx: usize) {
+    unsafe {
+        sscratch::write(dense_idx);
+        stvec::write(trap_entry as usize, stvec::TrapMode::Direct);
+    }
+}
+
+/* Dumps the trap state via the same debug console used by the panic handler
+ * and halts, since at this point the hart cannot safely resume.
+ */
+fn fatal_exception(cause: usize, epc: usize, tval: usize) -> ! {
+    debug_print!("Unhandled exception: cause={:#x} epc={:#x} tval={:#x}\n", cause, epc, tval);
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+#[no_mangle]
+extern "C" fn trap_handler() {
+    let cause = scause::read();
+    let epc = sepc::read();
+    let tval = stval::read();
+
+    if cause.is_interrupt() {
+        let dense_idx = sscratch::read();
+        dispatch_interrupt(cause.code(), dense_idx);
+    } else {
+        fatal_exception(cause.bits(), epc, tval);
+    }
+}
+
+/* Interrupts are dispatched by cause code (the low bits of `scause` with the
+ * high "is interrupt" bit stripped, as exposed by `riscv::register::scause`).
+ * Subsystems (timer, IPI, ...) hook in here.
+ */
+fn dispatch_interrupt(code: usize, dense_idx: usize) {
+    match code {
+        INTERRUPT_SUPERVISOR_TIMER => crate::timer::on_tick(dense_idx),
+        INTERRUPT_SUPERVISOR_SOFT => crate::ipi::on_ipi(dense_idx),
+        _ => debug_print!("Unhandled interrupt: code={}\n", code),
+    }
+}